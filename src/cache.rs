@@ -0,0 +1,446 @@
+//! An incremental, append-based on-disk cache for binned images
+//!
+//! A cache is a pair of files living in a cache directory: a small **docket** describing the
+//! collection layout, binning parameters, and which data file currently holds the cached
+//! counts; and a larger **data file** holding a sequence of appended partial histograms
+//! ("chunks"), each tagged with the index of the module it was folded from. Each cache update
+//! appends one chunk per module with newly-folded data and bumps the docket's `bytes_in_use`,
+//! rather than rewriting the whole data file. Readers always consult the docket first, so a
+//! half-written append can never corrupt a prior valid cache.
+//!
+//! Tagging chunks by module lets a single module be invalidated without discarding the others:
+//! the docket records, per module, the index of the first chunk that still validly contributes
+//! (`module_valid_from_chunk`), so rebuilding one module's contribution just raises its entry and
+//! appends a fresh chunk, while chunks belonging to untouched modules are still trusted.
+
+use crate::collection::{Error, FileStamp};
+use crate::image::{Crop, Image};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// The fraction of a data file's chunks which must be unreachable before it is compacted
+const COMPACTION_THRESHOLD: f64 = 0.5;
+
+/// The docket describing a cached binned image
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Docket {
+    /// A fingerprint of every data file held by each module when the cache was last updated,
+    /// used to detect files which have since changed, grown, or been replaced
+    module_file_stamps: Vec<Vec<FileStamp>>,
+    /// For each module, the index (in file order within the data file) of the first chunk which
+    /// still validly contributes; earlier chunks tagged with that module are stale and skipped
+    module_valid_from_chunk: Vec<usize>,
+    /// The detector width used to decode event positions
+    width: u16,
+    /// The detector height used to decode event positions
+    height: u16,
+    /// The crop region applied when binning, if any
+    crop: Option<Crop>,
+    /// The width of the cached image
+    image_width: u16,
+    /// The height of the cached image
+    image_height: u16,
+    /// A unique id identifying this docket's data file
+    data_file_id: String,
+    /// The number of bytes at the start of the data file holding valid, in-use chunks
+    bytes_in_use: u64,
+    /// The number of chunks within `bytes_in_use`
+    chunk_count: usize,
+}
+
+impl Docket {
+    /// The path of the docket file within `cache_dir`
+    fn path(cache_dir: &Path) -> PathBuf {
+        cache_dir.join("docket.json")
+    }
+
+    /// The path of this docket's data file within `cache_dir`
+    fn data_path(&self, cache_dir: &Path) -> PathBuf {
+        cache_dir.join(format!("{}.dat", self.data_file_id))
+    }
+
+    /// A fingerprint of every data file held by each module when the cache was last updated
+    pub(crate) fn module_file_stamps(&self) -> &[Vec<FileStamp>] {
+        &self.module_file_stamps
+    }
+
+    /// Whether this docket was written for the same binning parameters as `width`/`height`/`crop`
+    fn matches_parameters(&self, width: u16, height: u16, crop: Option<Crop>) -> bool {
+        self.width == width
+            && self.height == height
+            && self.crop.map(|c| (c.x0, c.y0, c.x1, c.y1))
+                == crop.map(|c| (c.x0, c.y0, c.x1, c.y1))
+    }
+
+    /// The index of the first chunk belonging to `module_idx` which still validly contributes
+    fn valid_from_chunk(&self, module_idx: usize) -> usize {
+        self.module_valid_from_chunk
+            .get(module_idx)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+/// The result of comparing a [`Collection`](crate::collection::Collection)'s current data files
+/// against a cached [`Docket`]
+#[derive(Debug, Clone)]
+pub enum CacheStatus {
+    /// Every previously-cached file is unchanged; only the given per-module file counts have
+    /// already been folded into the cache, so files beyond them are new and must be folded
+    Append {
+        /// Per-module file counts already folded into the cache
+        previous_file_counts: Vec<u32>,
+    },
+    /// One or more modules contain a file that changed size, modification time, or inode; those
+    /// modules' cached contribution can no longer be trusted and must be refolded from scratch,
+    /// while modules absent from `rebuild_modules` are still pure appends
+    Stale {
+        /// Per-module file counts already folded into the cache; entries for modules in
+        /// `rebuild_modules` are meaningless and should be treated as `0`
+        previous_file_counts: Vec<u32>,
+        /// Indices of modules whose cached contribution must be refolded from scratch
+        rebuild_modules: Vec<usize>,
+    },
+}
+
+/// The size, in bytes, of a single chunk for an image of the given dimensions: a little-endian
+/// `u32` module index header followed by the row-major `u64` counts
+fn chunk_bytes(width: u16, height: u16) -> u64 {
+    4 + width as u64 * height as u64 * 8
+}
+
+/// Generate a unique, random id for a new data file
+fn random_data_file_id() -> String {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(nanos);
+    let random = hasher.finish();
+    format!("{nanos:016x}{random:016x}")
+}
+
+/// Read a single chunk from `file` at the given chunk index, returning the module it belongs to
+/// and its counts
+fn read_chunk(
+    file: &mut File,
+    chunk_idx: usize,
+    width: u16,
+    height: u16,
+) -> Result<(usize, Image), Error> {
+    let offset = chunk_idx as u64 * chunk_bytes(width, height);
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut header = [0u8; 4];
+    file.read_exact(&mut header)?;
+    let module_idx = u32::from_le_bytes(header) as usize;
+
+    let mut buf = vec![0u8; width as usize * height as usize * 8];
+    file.read_exact(&mut buf)?;
+    let counts = buf
+        .chunks_exact(8)
+        .map(|bytes| u64::from_le_bytes(bytes.try_into().expect("chunk size is a multiple of 8")))
+        .collect();
+
+    Ok((module_idx, Image::from_counts(width, height, counts)))
+}
+
+/// Append a single chunk tagged with `module_idx` to `data_file` at `offset`
+fn write_chunk(data_file: &mut File, offset: u64, module_idx: usize, image: &Image) -> Result<(), Error> {
+    data_file.seek(SeekFrom::Start(offset))?;
+    data_file.write_all(&(module_idx as u32).to_le_bytes())?;
+    for count in image.counts() {
+        data_file.write_all(&count.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Load the cached per-module images for the given binning parameters from `cache_dir`, if a
+/// valid, matching docket is present
+///
+/// Returns one entry per module with at least one still-valid chunk; modules with no cached
+/// contribution (new modules, or ones fully invalidated and not yet refolded) are simply absent.
+/// Returns `None` if no cache exists yet, or the existing cache was written for different
+/// binning parameters.
+pub fn load(
+    cache_dir: &Path,
+    width: u16,
+    height: u16,
+    crop: Option<Crop>,
+) -> Result<Option<(Vec<(usize, Image)>, Docket)>, Error> {
+    let docket_path = Docket::path(cache_dir);
+    if !docket_path.exists() {
+        return Ok(None);
+    }
+
+    let docket: Docket = serde_json::from_slice(&fs::read(docket_path)?)?;
+    if !docket.matches_parameters(width, height, crop) {
+        return Ok(None);
+    }
+
+    let mut data_file = File::open(docket.data_path(cache_dir))?;
+    let mut by_module: Vec<(usize, Image)> = Vec::new();
+    for chunk_idx in 0..docket.chunk_count {
+        let (module_idx, chunk) = read_chunk(&mut data_file, chunk_idx, docket.image_width, docket.image_height)?;
+        if chunk_idx < docket.valid_from_chunk(module_idx) {
+            continue;
+        }
+        match by_module.iter_mut().find(|(idx, _)| *idx == module_idx) {
+            Some((_, image)) => image.merge(&chunk),
+            None => by_module.push((module_idx, chunk)),
+        }
+    }
+
+    Ok(Some((by_module, docket)))
+}
+
+/// Append one chunk per `(module_idx, delta)` pair in `module_deltas` to the cache in
+/// `cache_dir`, creating the cache if `existing` is `None`, and record `module_file_stamps` as
+/// the file fingerprints this update brings the cache up to date with
+///
+/// `image_width`/`image_height` are the dimensions of the (possibly cropped) cached image, as
+/// already computed by the caller from `crop`; they must be given explicitly rather than
+/// re-derived from `module_deltas`, since a cache can legitimately be first created from an empty
+/// `module_deltas` (e.g. every module has zero data files when a long acquisition starts).
+///
+/// Every module index in `rebuild_modules` has its previously-cached chunks marked unreachable
+/// before the new chunks are appended, so `module_deltas` must fold those modules from scratch
+/// rather than just their newly-appended files. The data file is then compacted down to its
+/// still-reachable chunks, reclaiming the unreachable ones, once the unreachable fraction exceeds
+/// [`COMPACTION_THRESHOLD`].
+#[allow(clippy::too_many_arguments)]
+pub fn write(
+    cache_dir: &Path,
+    module_deltas: &[(usize, Image)],
+    width: u16,
+    height: u16,
+    crop: Option<Crop>,
+    image_width: u16,
+    image_height: u16,
+    module_file_stamps: Vec<Vec<FileStamp>>,
+    existing: Option<Docket>,
+    rebuild_modules: &[usize],
+) -> Result<(), Error> {
+    fs::create_dir_all(cache_dir)?;
+
+    let mut docket = existing.unwrap_or_else(|| Docket {
+        module_file_stamps: Vec::new(),
+        module_valid_from_chunk: Vec::new(),
+        width,
+        height,
+        crop,
+        image_width,
+        image_height,
+        data_file_id: random_data_file_id(),
+        bytes_in_use: 0,
+        chunk_count: 0,
+    });
+
+    if docket.module_valid_from_chunk.len() < module_file_stamps.len() {
+        docket
+            .module_valid_from_chunk
+            .resize(module_file_stamps.len(), 0);
+    }
+    for &module_idx in rebuild_modules {
+        if module_idx < docket.module_valid_from_chunk.len() {
+            docket.module_valid_from_chunk[module_idx] = docket.chunk_count;
+        }
+    }
+
+    let chunk_size = chunk_bytes(docket.image_width, docket.image_height);
+    let mut data_file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(docket.data_path(cache_dir))?;
+    for (module_idx, delta) in module_deltas {
+        write_chunk(&mut data_file, docket.bytes_in_use, *module_idx, delta)?;
+        docket.bytes_in_use += chunk_size;
+        docket.chunk_count += 1;
+    }
+
+    docket.module_file_stamps = module_file_stamps;
+
+    let (valid, total) = chunk_validity(cache_dir, &docket)?;
+    if total > 0 && (total - valid) as f64 / total as f64 > COMPACTION_THRESHOLD {
+        compact(cache_dir, &mut docket)?;
+    }
+
+    write_docket(cache_dir, &docket)?;
+    Ok(())
+}
+
+/// Persist `docket` to `cache_dir`
+///
+/// Writes to a temporary file in `cache_dir` first and renames it over `docket.json`, so a
+/// crash or power loss mid-write never leaves the docket itself (the one file every read path
+/// trusts first) truncated or corrupt.
+fn write_docket(cache_dir: &Path, docket: &Docket) -> Result<(), Error> {
+    let temp_path = cache_dir.join(format!("docket.json.{}.tmp", random_data_file_id()));
+    fs::write(&temp_path, serde_json::to_vec(docket)?)?;
+    fs::rename(&temp_path, Docket::path(cache_dir))?;
+    Ok(())
+}
+
+/// Count how many of the docket's chunks are still reachable (valid) versus the total chunk count
+fn chunk_validity(cache_dir: &Path, docket: &Docket) -> Result<(usize, usize), Error> {
+    let mut data_file = File::open(docket.data_path(cache_dir))?;
+    let mut valid = 0;
+    for chunk_idx in 0..docket.chunk_count {
+        let (module_idx, _) = read_chunk(&mut data_file, chunk_idx, docket.image_width, docket.image_height)?;
+        if chunk_idx >= docket.valid_from_chunk(module_idx) {
+            valid += 1;
+        }
+    }
+    Ok((valid, docket.chunk_count))
+}
+
+/// Rewrite the docket's data file keeping only its still-reachable chunks, discarding unreachable
+/// ones and repointing `docket` at a fresh file under a new random id
+fn compact(cache_dir: &Path, docket: &mut Docket) -> Result<(), Error> {
+    let mut data_file = File::open(docket.data_path(cache_dir))?;
+    let mut kept = Vec::new();
+    for chunk_idx in 0..docket.chunk_count {
+        let (module_idx, chunk) = read_chunk(&mut data_file, chunk_idx, docket.image_width, docket.image_height)?;
+        if chunk_idx >= docket.valid_from_chunk(module_idx) {
+            kept.push((module_idx, chunk));
+        }
+    }
+
+    let old_data_path = docket.data_path(cache_dir);
+    docket.data_file_id = random_data_file_id();
+
+    let chunk_size = chunk_bytes(docket.image_width, docket.image_height);
+    let mut new_data_file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(docket.data_path(cache_dir))?;
+    for (chunk_idx, (module_idx, chunk)) in kept.iter().enumerate() {
+        write_chunk(&mut new_data_file, chunk_idx as u64 * chunk_size, *module_idx, chunk)?;
+    }
+
+    docket.chunk_count = kept.len();
+    docket.bytes_in_use = kept.len() as u64 * chunk_size;
+    // Every kept chunk just passed its validity check, so each module's chunks are all valid
+    // again starting from the beginning of the freshly written file
+    docket.module_valid_from_chunk.fill(0);
+    fs::remove_file(old_data_path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh, empty cache directory under the system temp dir, unique to the calling test
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "tristimg-cache-test-{name}-{:x}",
+            std::process::id()
+        ));
+        _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// The merged image across every module returned by [`load`], for tests that don't care
+    /// about per-module attribution
+    fn load_merged(cache_dir: &Path, width: u16, height: u16, crop: Option<Crop>) -> Option<Image> {
+        let (module_images, docket) = load(cache_dir, width, height, crop).unwrap()?;
+        let mut image = Image::new(docket.image_width, docket.image_height);
+        for (_, module_image) in module_images {
+            image.merge(&module_image);
+        }
+        Some(image)
+    }
+
+    #[test]
+    fn write_then_load_round_trips_counts() {
+        let dir = cache_dir("round-trip");
+        let delta = Image::from_counts(2, 1, vec![1, 2]);
+
+        write(&dir, &[(0, delta)], 2, 1, None, 2, 1, vec![vec![]], None, &[]).unwrap();
+        let image = load_merged(&dir, 2, 1, None).unwrap();
+        let (_, docket) = load(&dir, 2, 1, None).unwrap().unwrap();
+
+        assert_eq!(image.counts(), &[1, 2]);
+        assert_eq!(docket.module_file_stamps(), &[vec![]]);
+    }
+
+    #[test]
+    fn appending_a_second_delta_merges_with_the_first() {
+        let dir = cache_dir("append");
+        let first = Image::from_counts(2, 1, vec![1, 2]);
+        let second = Image::from_counts(2, 1, vec![10, 20]);
+
+        write(&dir, &[(0, first)], 2, 1, None, 2, 1, vec![vec![]], None, &[]).unwrap();
+        let (_, docket) = load(&dir, 2, 1, None).unwrap().unwrap();
+        write(&dir, &[(0, second)], 2, 1, None, 2, 1, vec![vec![]], Some(docket), &[]).unwrap();
+
+        let image = load_merged(&dir, 2, 1, None).unwrap();
+        assert_eq!(image.counts(), &[11, 22]);
+    }
+
+    #[test]
+    fn rebuilding_one_module_does_not_disturb_another_modules_chunks() {
+        let dir = cache_dir("per-module");
+        let module_0 = Image::from_counts(2, 1, vec![1, 2]);
+        let module_1 = Image::from_counts(2, 1, vec![5, 5]);
+        let module_0_rebuilt = Image::from_counts(2, 1, vec![100, 200]);
+
+        write(
+            &dir,
+            &[(0, module_0), (1, module_1)],
+            2,
+            1,
+            None,
+            2,
+            1,
+            vec![vec![], vec![]],
+            None,
+            &[],
+        )
+        .unwrap();
+        let (_, docket) = load(&dir, 2, 1, None).unwrap().unwrap();
+
+        // Module 0 changed underneath us and must be refolded from scratch; module 1 is
+        // untouched and keeps its cached chunk
+        write(
+            &dir,
+            &[(0, module_0_rebuilt)],
+            2,
+            1,
+            None,
+            2,
+            1,
+            vec![vec![], vec![]],
+            Some(docket),
+            &[0],
+        )
+        .unwrap();
+
+        let image = load_merged(&dir, 2, 1, None).unwrap();
+        assert_eq!(image.counts(), &[105, 205]);
+    }
+
+    #[test]
+    fn load_returns_none_when_no_cache_exists() {
+        let dir = cache_dir("missing");
+        assert!(load(&dir, 2, 1, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn load_returns_none_when_parameters_differ() {
+        let dir = cache_dir("mismatch");
+        let delta = Image::from_counts(2, 1, vec![1, 2]);
+
+        write(&dir, &[(0, delta)], 2, 1, None, 2, 1, vec![vec![]], None, &[]).unwrap();
+
+        assert!(load(&dir, 3, 1, None).unwrap().is_none());
+    }
+}
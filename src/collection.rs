@@ -1,6 +1,23 @@
+use crate::cache::{CacheStatus, Docket};
+use crate::image::{Crop, Image};
 use hdf5::File;
 use ptree::{item::StringItem, TreeBuilder};
+use serde::{Deserialize, Serialize};
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::thread;
+
+/// Resolve a requested worker count to a concrete number of threads, treating `0` as "use
+/// all available CPUs"
+fn resolve_workers(requested: usize) -> usize {
+    if requested == 0 {
+        thread::available_parallelism()
+            .map(|count| count.get())
+            .unwrap_or(1)
+    } else {
+        requested
+    }
+}
 
 /// An error arising from loading a collection
 #[derive(Debug, thiserror::Error)]
@@ -15,6 +32,37 @@ pub enum Error {
     NoParentDirecory,
     #[error("Dataset {0} not found in file")]
     DatasetNotFound(String),
+    #[error("Error encountered performing cache I/O: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Error (de)serialising cache docket: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("Collection contains no data files")]
+    NoDataFiles,
+    #[error("Detector width must be non-zero")]
+    ZeroWidth,
+}
+
+/// A point-in-time fingerprint of a data file, used to detect whether it has since changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    /// The file's inode number
+    inode: u64,
+    /// The file's modification time, in seconds since the epoch
+    mtime: i64,
+    /// The file's size, in bytes
+    size: u64,
+}
+
+impl FileStamp {
+    /// Stat the file at `path`, producing its current fingerprint
+    fn of(path: &Path) -> Result<Self, Error> {
+        let meta = std::fs::metadata(path)?;
+        Ok(Self {
+            inode: meta.ino(),
+            mtime: meta.mtime(),
+            size: meta.size(),
+        })
+    }
 }
 
 /// A detector module
@@ -24,6 +72,40 @@ pub struct Module {
     data_files: Vec<File>,
 }
 
+impl Module {
+    /// The number of data files belonging to this module
+    fn file_count(&self) -> u32 {
+        self.data_files.len() as u32
+    }
+
+    /// Fingerprint every data file belonging to this module, in file order
+    fn file_stamps(&self) -> Result<Vec<FileStamp>, Error> {
+        self.data_files
+            .iter()
+            .map(|data_file| FileStamp::of(&PathBuf::from(data_file.filename())))
+            .collect()
+    }
+
+    /// Fold every event recorded from the `skip`-th data file onwards into `image`
+    ///
+    /// Used to fold only data files appended since a cache was last written.
+    fn fold_from_into(
+        &self,
+        skip: usize,
+        image: &mut Image,
+        width: u16,
+        crop: Option<Crop>,
+    ) -> Result<(), Error> {
+        for data_file in self.data_files.iter().skip(skip) {
+            let events = Event::from_file(data_file, width)?;
+            for &(x, y) in events.position() {
+                image.add(x, y, crop);
+            }
+        }
+        Ok(())
+    }
+}
+
 /// A data collection
 #[derive(Debug)]
 pub struct Collection {
@@ -33,7 +115,14 @@ pub struct Collection {
 
 impl Collection {
     /// Load a [`Collection`] from the NeXus file definition
-    pub fn from_nexus(path: PathBuf, datafile_zero_padding: usize) -> Result<Self, Error> {
+    ///
+    /// Module data files are opened concurrently across `workers` worker threads; a `workers`
+    /// of `0` uses the number of available CPUs.
+    pub fn from_nexus(
+        path: PathBuf,
+        datafile_zero_padding: usize,
+        workers: usize,
+    ) -> Result<Self, Error> {
         let file = File::open(&path)?;
         let meta = file.group("/entry/data/meta_file")?;
 
@@ -42,28 +131,290 @@ impl Collection {
             .file_stem()
             .ok_or(Error::NoFileStem)?
             .to_str()
-            .ok_or(Error::NoFileStem)?;
+            .ok_or(Error::NoFileStem)?
+            .to_owned();
         let directory = path.parent().ok_or(Error::NoParentDirecory)?.to_owned();
 
-        let mut modules = Vec::new();
-        let mut file_number_offset = 0;
+        let mut module_file_ranges = Vec::with_capacity(module_file_counts.len());
+        let mut file_number_offset = 0u32;
         for module_file_count in module_file_counts {
-            let mut data_files = Vec::new();
-            for file_idx in 1..=module_file_count {
-                let file_number = file_number_offset + file_idx;
-                let data_file_name =
-                    format!("{datafile_prefix}_{file_number:0>datafile_zero_padding$}.h5");
-                let mut data_file_path = directory.clone();
-                data_file_path.push(data_file_name);
-                data_files.push(File::open(&data_file_path)?);
-            }
-            modules.push(Module { data_files });
+            module_file_ranges
+                .push((file_number_offset + 1)..=(file_number_offset + module_file_count));
             file_number_offset += module_file_count;
         }
 
+        let worker_count = resolve_workers(workers).max(1);
+        let chunk_size = module_file_ranges.len().div_ceil(worker_count).max(1);
+
+        let modules = thread::scope(|scope| -> Result<Vec<Module>, Error> {
+            let handles: Vec<_> = module_file_ranges
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    let directory = directory.clone();
+                    let datafile_prefix = datafile_prefix.clone();
+                    let chunk = chunk.to_vec();
+                    scope.spawn(move || -> Result<Vec<Module>, Error> {
+                        chunk
+                            .into_iter()
+                            .map(|file_numbers| {
+                                let data_files = file_numbers
+                                    .map(|file_number| {
+                                        let data_file_name = format!(
+                                            "{datafile_prefix}_{file_number:0>datafile_zero_padding$}.h5"
+                                        );
+                                        let mut data_file_path = directory.clone();
+                                        data_file_path.push(data_file_name);
+                                        File::open(&data_file_path)
+                                    })
+                                    .collect::<hdf5::Result<Vec<_>>>()?;
+                                Ok(Module { data_files })
+                            })
+                            .collect()
+                    })
+                })
+                .collect();
+
+            let mut modules = Vec::new();
+            for handle in handles {
+                modules.extend(handle.join().expect("worker thread panicked")?);
+            }
+            Ok(modules)
+        })?;
+
         Ok(Self { modules })
     }
 
+    /// Bin every event across every module into a single 2D histogram [`Image`]
+    ///
+    /// `width` and `height` describe the full detector used to decode event pixel positions;
+    /// `crop`, if given, restricts the resulting image to a sub-region of the detector. Modules
+    /// are folded concurrently across `workers` worker threads, each accumulating its own
+    /// partial image which is then reduced into the final result; a `workers` of `0` uses the
+    /// number of available CPUs.
+    pub fn bin(
+        &self,
+        width: u16,
+        height: u16,
+        crop: Option<Crop>,
+        workers: usize,
+    ) -> Result<Image, Error> {
+        self.bin_delta(&[], width, height, crop, workers)
+    }
+
+    /// The number of data files currently held by each module, in module order
+    ///
+    /// Compared against a previously recorded set of counts, this identifies which files are
+    /// new since a cache was last written.
+    pub fn module_file_counts(&self) -> Vec<u32> {
+        self.modules.iter().map(Module::file_count).collect()
+    }
+
+    /// Fingerprint every data file across every module, in module order
+    pub fn file_stamps(&self) -> Result<Vec<Vec<FileStamp>>, Error> {
+        self.modules.iter().map(Module::file_stamps).collect()
+    }
+
+    /// Compare this collection's current data files against a cache's [`Docket`], determining,
+    /// per module, whether its cached contribution can simply be extended with newly-appended
+    /// files or must be refolded from scratch
+    ///
+    /// Cached data is accumulated per-module, so a module with fewer files than were cached, or
+    /// with a previously-cached file whose size, modification time, or inode has changed, only
+    /// invalidates that module's contribution; modules that only gained new files keep reusing
+    /// theirs.
+    pub fn validate_cache(&self, docket: &Docket) -> Result<CacheStatus, Error> {
+        let current_stamps = self.file_stamps()?;
+        let previous_stamps = docket.module_file_stamps();
+
+        let mut previous_file_counts = Vec::with_capacity(previous_stamps.len());
+        let mut rebuild_modules = Vec::new();
+
+        for (module_idx, previous) in previous_stamps.iter().enumerate() {
+            let current = current_stamps
+                .get(module_idx)
+                .map(Vec::as_slice)
+                .unwrap_or(&[]);
+            if current.len() < previous.len() || current[..previous.len()] != previous[..] {
+                rebuild_modules.push(module_idx);
+                previous_file_counts.push(0);
+            } else {
+                previous_file_counts.push(previous.len() as u32);
+            }
+        }
+
+        if rebuild_modules.is_empty() {
+            Ok(CacheStatus::Append {
+                previous_file_counts,
+            })
+        } else {
+            Ok(CacheStatus::Stale {
+                previous_file_counts,
+                rebuild_modules,
+            })
+        }
+    }
+
+    /// Bin only the events recorded since `previous_file_counts` into a single 2D histogram
+    /// [`Image`]
+    ///
+    /// `previous_file_counts` gives, per module, how many of its data files have already been
+    /// folded elsewhere (e.g. into a cache); modules beyond the end of `previous_file_counts`
+    /// are treated as entirely new. See [`Collection::bin`] for the meaning of the remaining
+    /// arguments.
+    pub fn bin_delta(
+        &self,
+        previous_file_counts: &[u32],
+        width: u16,
+        height: u16,
+        crop: Option<Crop>,
+        workers: usize,
+    ) -> Result<Image, Error> {
+        if width == 0 {
+            return Err(Error::ZeroWidth);
+        }
+
+        let (image_width, image_height) =
+            crop.map_or((width, height), |crop| (crop.width(), crop.height()));
+
+        let worker_count = resolve_workers(workers).max(1).min(self.modules.len().max(1));
+        let chunk_size = self.modules.len().div_ceil(worker_count.max(1)).max(1);
+        let modules_with_skip: Vec<(&Module, usize)> = self
+            .modules
+            .iter()
+            .enumerate()
+            .map(|(idx, module)| {
+                let skip = previous_file_counts
+                    .get(idx)
+                    .copied()
+                    .unwrap_or(0) as usize;
+                (module, skip)
+            })
+            .collect();
+
+        let partials = thread::scope(|scope| -> Result<Vec<Image>, Error> {
+            let handles: Vec<_> = modules_with_skip
+                .chunks(chunk_size.max(1))
+                .map(|chunk| {
+                    scope.spawn(move || -> Result<Image, Error> {
+                        let mut image = Image::new(image_width, image_height);
+                        for (module, skip) in chunk {
+                            module.fold_from_into(*skip, &mut image, width, crop)?;
+                        }
+                        Ok(image)
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("worker thread panicked"))
+                .collect()
+        })?;
+
+        let mut image = Image::new(image_width, image_height);
+        for partial in partials {
+            image.merge(&partial);
+        }
+        Ok(image)
+    }
+
+    /// Bin only the events recorded since `previous_file_counts` into one partial [`Image`] per
+    /// module, keyed by module index
+    ///
+    /// Modules listed in `rebuild_modules` are folded from scratch regardless of their
+    /// `previous_file_counts` entry, since their cached contribution can no longer be trusted.
+    /// Modules with nothing new to fold (no appended files, and not being rebuilt) are simply
+    /// absent from the result. See [`Collection::bin`] for the meaning of the remaining
+    /// arguments.
+    pub fn bin_delta_per_module(
+        &self,
+        previous_file_counts: &[u32],
+        rebuild_modules: &[usize],
+        width: u16,
+        height: u16,
+        crop: Option<Crop>,
+        workers: usize,
+    ) -> Result<Vec<(usize, Image)>, Error> {
+        if width == 0 {
+            return Err(Error::ZeroWidth);
+        }
+
+        let (image_width, image_height) =
+            crop.map_or((width, height), |crop| (crop.width(), crop.height()));
+
+        let modules_with_skip: Vec<(usize, &Module, usize)> = self
+            .modules
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, module)| {
+                let skip = if rebuild_modules.contains(&idx) {
+                    0
+                } else {
+                    previous_file_counts.get(idx).copied().unwrap_or(0) as usize
+                };
+                (skip < module.file_count() as usize).then_some((idx, module, skip))
+            })
+            .collect();
+
+        let worker_count = resolve_workers(workers)
+            .max(1)
+            .min(modules_with_skip.len().max(1));
+        let chunk_size = modules_with_skip.len().div_ceil(worker_count.max(1)).max(1);
+
+        thread::scope(|scope| -> Result<Vec<(usize, Image)>, Error> {
+            let handles: Vec<_> = modules_with_skip
+                .chunks(chunk_size.max(1))
+                .map(|chunk| {
+                    scope.spawn(move || -> Result<Vec<(usize, Image)>, Error> {
+                        chunk
+                            .iter()
+                            .map(|(idx, module, skip)| {
+                                let mut image = Image::new(image_width, image_height);
+                                module.fold_from_into(*skip, &mut image, width, crop)?;
+                                Ok((*idx, image))
+                            })
+                            .collect()
+                    })
+                })
+                .collect();
+
+            let mut deltas = Vec::new();
+            for handle in handles {
+                deltas.extend(handle.join().expect("worker thread panicked")?);
+            }
+            Ok(deltas)
+        })
+    }
+
+    /// List the dataset names present in the collection's first data file
+    ///
+    /// Every data file written by a given NeXus acquisition shares the same dataset layout, so
+    /// this is a quick way to discover available keys without reopening files.
+    pub fn dataset_names(&self) -> Result<Vec<String>, Error> {
+        let file = self
+            .modules
+            .iter()
+            .flat_map(|module| module.data_files.iter())
+            .next()
+            .ok_or(Error::NoDataFiles)?;
+        Ok(file.member_names()?)
+    }
+
+    /// Read dataset `key` from every data file in the collection, returning one vector of
+    /// values per file, in module then file order
+    pub fn read_dataset(&self, key: &str) -> Result<Vec<Vec<f64>>, Error> {
+        self.modules
+            .iter()
+            .flat_map(|module| module.data_files.iter())
+            .map(|data_file| {
+                let dataset = data_file
+                    .dataset(key)
+                    .map_err(|_| Error::DatasetNotFound(key.to_string()))?;
+                Ok(dataset.read_1d::<f64>()?.to_vec())
+            })
+            .collect()
+    }
+
     /// Produces a [`ptree`] tree for degug visualisation
     pub fn as_tree(&self) -> StringItem {
         let mut tree = TreeBuilder::new("collection".to_string());
@@ -87,15 +438,28 @@ pub struct Event {
 }
 
 impl Event {
-    /// Read a event
-    pub fn read_event<P: AsRef<Path>>(path: P) -> Result<Vec<u32>, Error> {
-        let file = File::open(path)?;
-        if let Ok(dataset) = file.dataset("event_id") {
-            let values: Vec<u32> = dataset.read_1d()?.to_vec();
-            Ok(values)
-        } else {
-            Err(Error::DatasetNotFound("event_id".to_string()))
-        }
+    /// Read the events recorded in `file`, decoding each event's `(x, y)` pixel position
+    /// from its `event_id`, assuming a detector module of the given `width`
+    pub fn from_file(file: &File, width: u16) -> Result<Self, Error> {
+        let dataset = file
+            .dataset("event_id")
+            .map_err(|_| Error::DatasetNotFound("event_id".to_string()))?;
+        let event_id: Vec<u32> = dataset.read_1d()?.to_vec();
+        let position = event_id
+            .iter()
+            .map(|&id| ((id % width as u32) as u16, (id / width as u32) as u16))
+            .collect();
+        Ok(Self { event_id, position })
+    }
+
+    /// The raw event ids
+    pub fn event_id(&self) -> &[u32] {
+        &self.event_id
+    }
+
+    /// The decoded `(x, y)` pixel positions of each event
+    pub fn position(&self) -> &[(u16, u16)] {
+        &self.position
     }
 }
 
@@ -115,3 +479,125 @@ pub fn read_hdf5_data<P: AsRef<Path>>(path: P, keys: &[&str]) -> Result<Vec<Vec<
 
     Ok(data)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache;
+
+    /// Create a temporary HDF5 file containing an `event_id` dataset with the given ids
+    fn event_id_file(name: &str, event_id: &[u32]) -> File {
+        event_id_file_at(
+            &std::env::temp_dir().join(format!(
+                "tristimg-test-{name}-{:x}.h5",
+                std::process::id()
+            )),
+            event_id,
+        )
+    }
+
+    /// (Re)create an HDF5 file at `path` containing an `event_id` dataset with the given ids,
+    /// overwriting whatever was there before
+    fn event_id_file_at(path: &Path, event_id: &[u32]) -> File {
+        _ = std::fs::remove_file(path);
+        let file = File::create(path).unwrap();
+        file.new_dataset::<u32>()
+            .shape(event_id.len())
+            .create("event_id")
+            .unwrap()
+            .write_raw(event_id)
+            .unwrap();
+        file
+    }
+
+    #[test]
+    fn event_position_decodes_from_id_and_width() {
+        let width = 4;
+        let file = event_id_file("decode", &[0, 1, 5, 9]);
+
+        let events = Event::from_file(&file, width).unwrap();
+
+        assert_eq!(events.event_id(), &[0, 1, 5, 9]);
+        assert_eq!(events.position(), &[(0, 0), (1, 0), (1, 1), (1, 2)]);
+    }
+
+    #[test]
+    fn event_position_wraps_at_detector_width() {
+        let width = 3;
+        let file = event_id_file("wrap", &[2, 3, 8]);
+
+        let events = Event::from_file(&file, width).unwrap();
+
+        assert_eq!(events.position(), &[(2, 0), (0, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn validate_cache_only_marks_the_rewritten_module_for_rebuild() {
+        let module_0_path = std::env::temp_dir().join(format!(
+            "tristimg-test-validate-cache-module-0-{:x}.h5",
+            std::process::id()
+        ));
+        let module_1_path = std::env::temp_dir().join(format!(
+            "tristimg-test-validate-cache-module-1-{:x}.h5",
+            std::process::id()
+        ));
+        let cache_dir = std::env::temp_dir().join(format!(
+            "tristimg-test-validate-cache-dir-{:x}",
+            std::process::id()
+        ));
+        _ = std::fs::remove_dir_all(&cache_dir);
+
+        let module_0_file = event_id_file_at(&module_0_path, &[0, 1]);
+        let module_1_file = event_id_file_at(&module_1_path, &[2, 3]);
+        let collection = Collection {
+            modules: vec![
+                Module {
+                    data_files: vec![module_0_file],
+                },
+                Module {
+                    data_files: vec![module_1_file],
+                },
+            ],
+        };
+
+        cache::write(
+            &cache_dir,
+            &[],
+            4,
+            4,
+            None,
+            4,
+            4,
+            collection.file_stamps().unwrap(),
+            None,
+            &[],
+        )
+        .unwrap();
+        let (_, docket) = cache::load(&cache_dir, 4, 4, None).unwrap().unwrap();
+
+        // Rewrite module 0's file in place (same path, different size); module 1 is untouched
+        let module_0_file = event_id_file_at(&module_0_path, &[0, 1, 5]);
+        let module_1_file = File::open(&module_1_path).unwrap();
+        let collection = Collection {
+            modules: vec![
+                Module {
+                    data_files: vec![module_0_file],
+                },
+                Module {
+                    data_files: vec![module_1_file],
+                },
+            ],
+        };
+
+        match collection.validate_cache(&docket).unwrap() {
+            CacheStatus::Stale {
+                previous_file_counts,
+                rebuild_modules,
+            } => {
+                assert_eq!(rebuild_modules, vec![0]);
+                assert_eq!(previous_file_counts[1], 1);
+            }
+            CacheStatus::Append { .. } => panic!("expected the rewritten module to be stale"),
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use crate::collection::Error;
+use hdf5::File;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// A rectangular region of the detector, used to crop a binned [`Image`] to a
+/// subset of pixels
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Crop {
+    /// Lower (inclusive) x bound, in detector pixels
+    pub x0: u16,
+    /// Lower (inclusive) y bound, in detector pixels
+    pub y0: u16,
+    /// Upper (exclusive) x bound, in detector pixels
+    pub x1: u16,
+    /// Upper (exclusive) y bound, in detector pixels
+    pub y1: u16,
+}
+
+impl Crop {
+    /// The width, in pixels, of the cropped region
+    pub fn width(&self) -> u16 {
+        self.x1.saturating_sub(self.x0)
+    }
+
+    /// The height, in pixels, of the cropped region
+    pub fn height(&self) -> u16 {
+        self.y1.saturating_sub(self.y0)
+    }
+}
+
+/// A 2D histogram of per-pixel event counts, accumulated across a [`Collection`](crate::collection::Collection)
+#[derive(Debug, Clone)]
+pub struct Image {
+    /// The width of the image, in pixels
+    width: u16,
+    /// The height of the image, in pixels
+    height: u16,
+    /// Per-pixel event counts, stored row-major
+    counts: Vec<u64>,
+}
+
+impl Image {
+    /// Create a new, empty image of the given dimensions
+    pub fn new(width: u16, height: u16) -> Self {
+        Self {
+            width,
+            height,
+            counts: vec![0; width as usize * height as usize],
+        }
+    }
+
+    /// Reconstruct an image from previously accumulated per-pixel counts
+    ///
+    /// `counts` must have `width * height` elements, as produced by [`Image::counts`].
+    pub fn from_counts(width: u16, height: u16, counts: Vec<u64>) -> Self {
+        debug_assert_eq!(counts.len(), width as usize * height as usize);
+        Self {
+            width,
+            height,
+            counts,
+        }
+    }
+
+    /// The width of the image, in pixels
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    /// The height of the image, in pixels
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    /// The per-pixel event counts, stored row-major
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// Record a single event at detector pixel `(x, y)`, optionally restricting to `crop`
+    ///
+    /// Events outside the image bounds (or outside `crop`, if given) are silently dropped.
+    pub fn add(&mut self, x: u16, y: u16, crop: Option<Crop>) {
+        let (x, y) = match crop {
+            Some(crop) => {
+                if x < crop.x0 || x >= crop.x1 || y < crop.y0 || y >= crop.y1 {
+                    return;
+                }
+                (x - crop.x0, y - crop.y0)
+            }
+            None => (x, y),
+        };
+        if x < self.width && y < self.height {
+            self.counts[y as usize * self.width as usize + x as usize] += 1;
+        }
+    }
+
+    /// Merge another image's counts into this one
+    ///
+    /// Both images must share the same dimensions.
+    pub fn merge(&mut self, other: &Image) {
+        for (a, b) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *a += b;
+        }
+    }
+
+    /// Write this image to a new HDF5 file at `path`, as a 2D `counts` dataset
+    pub fn write_hdf5<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let file = File::create(path)?;
+        let dataset = file
+            .new_dataset::<u64>()
+            .shape((self.height as usize, self.width as usize))
+            .create("counts")?;
+        let data = ndarray::Array2::from_shape_vec(
+            (self.height as usize, self.width as usize),
+            self.counts.clone(),
+        )
+        .expect("counts length always matches width * height");
+        dataset.write(&data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_accumulates_per_pixel_counts() {
+        let mut image = Image::new(4, 3);
+        image.add(1, 2, None);
+        image.add(1, 2, None);
+        image.add(3, 0, None);
+
+        assert_eq!(image.counts()[2 * 4 + 1], 2);
+        assert_eq!(image.counts()[3], 1);
+        assert_eq!(image.counts().iter().sum::<u64>(), 3);
+    }
+
+    #[test]
+    fn add_drops_events_outside_the_image() {
+        let mut image = Image::new(2, 2);
+        image.add(5, 5, None);
+
+        assert_eq!(image.counts().iter().sum::<u64>(), 0);
+    }
+
+    #[test]
+    fn add_with_crop_translates_into_cropped_coordinates() {
+        let crop = Crop {
+            x0: 2,
+            y0: 2,
+            x1: 4,
+            y1: 4,
+        };
+        let mut image = Image::new(crop.width(), crop.height());
+
+        image.add(1, 1, Some(crop));
+        image.add(3, 3, Some(crop));
+
+        assert_eq!(image.counts().iter().sum::<u64>(), 1);
+        assert_eq!(image.counts()[1 * 2 + 1], 1);
+    }
+
+    #[test]
+    fn merge_sums_matching_pixels() {
+        let mut a = Image::from_counts(2, 1, vec![1, 2]);
+        let b = Image::from_counts(2, 1, vec![10, 20]);
+
+        a.merge(&b);
+
+        assert_eq!(a.counts(), &[11, 22]);
+    }
+
+    #[test]
+    fn write_hdf5_round_trips_counts() {
+        let image = Image::from_counts(2, 2, vec![1, 2, 3, 4]);
+        let path = std::env::temp_dir().join(format!(
+            "tristimg-test-{:x}.h5",
+            std::process::id()
+        ));
+
+        image.write_hdf5(&path).unwrap();
+
+        let file = hdf5::File::open(&path).unwrap();
+        let counts: Vec<u64> = file.dataset("counts").unwrap().read_raw().unwrap();
+        assert_eq!(counts, vec![1, 2, 3, 4]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
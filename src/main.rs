@@ -3,11 +3,18 @@
 #![warn(missing_docs)]
 #![warn(clippy::missing_docs_in_private_items)]
 
+/// An incremental, append-based on-disk cache for binned images
+mod cache;
 /// Utilities for loading data collections from NeXus / HDF5
 mod collection;
+/// 2D histogram images produced by binning event data
+mod image;
+/// An interactive REPL for exploring a loaded [`collection::Collection`]
+mod repl;
 
 use clap::{Parser, Subcommand};
 use collection::{read_hdf5_data, Collection};
+use image::{Crop, Image};
 use ptree::print_tree;
 use std::path::PathBuf;
 use tracing::Level;
@@ -32,6 +39,81 @@ enum Commands {
         #[clap(subcommand)]
         subcommand: DebugCommands,
     },
+    /// Bin event mode data into a 2D histogram image
+    Bin(BinCommand),
+    /// Open a collection once and explore it interactively
+    Explore(ExploreCommand),
+}
+
+#[derive(Debug, Clone, Parser)]
+struct ExploreCommand {
+    /// The path to the NeXus file which describes the data collection
+    #[clap(long, env = "NEXUS_FILE")]
+    nexus_path: PathBuf,
+    /// The width to which the count field in data file names should be padded
+    #[clap(long, env = "DATA_FILE_PADDING", default_value_t = 6)]
+    data_file_padding: usize,
+    /// The number of worker threads to use for loading data and for the `bin` command,
+    /// defaulting to the number of available CPUs when `0`
+    #[clap(long, default_value_t = 0)]
+    workers: usize,
+    /// The detector width, in pixels, used by the `bin` command
+    #[clap(long)]
+    width: u16,
+    /// The detector height, in pixels, used by the `bin` command
+    #[clap(long)]
+    height: u16,
+}
+
+#[derive(Debug, Clone, Parser)]
+struct BinCommand {
+    /// The path to the NeXus file which describes the data collection
+    #[clap(long, env = "NEXUS_FILE")]
+    nexus_path: PathBuf,
+    /// The width to which the count field in data file names should be padded
+    #[clap(long, env = "DATA_FILE_PADDING", default_value_t = 6)]
+    data_file_padding: usize,
+    /// The number of worker threads to use for loading and folding data, defaulting to the
+    /// number of available CPUs when `0`
+    #[clap(long, default_value_t = 0)]
+    workers: usize,
+    /// The width of the detector, in pixels
+    #[clap(long)]
+    width: u16,
+    /// The height of the detector, in pixels
+    #[clap(long)]
+    height: u16,
+    /// Lower (inclusive) x bound of an optional crop region
+    #[clap(long, requires_all = ["crop_y0", "crop_x1", "crop_y1"])]
+    crop_x0: Option<u16>,
+    /// Lower (inclusive) y bound of an optional crop region
+    #[clap(long, requires_all = ["crop_x0", "crop_x1", "crop_y1"])]
+    crop_y0: Option<u16>,
+    /// Upper (exclusive) x bound of an optional crop region
+    #[clap(long, requires_all = ["crop_x0", "crop_y0", "crop_y1"])]
+    crop_x1: Option<u16>,
+    /// Upper (exclusive) y bound of an optional crop region
+    #[clap(long, requires_all = ["crop_x0", "crop_y0", "crop_x1"])]
+    crop_y1: Option<u16>,
+    /// The path to which the binned image should be written, as an HDF5 file
+    #[clap(long)]
+    output: PathBuf,
+    /// A directory in which to keep an incremental cache of previously binned data, so that
+    /// repeated runs over a growing acquisition only fold newly-appended files
+    #[clap(long)]
+    cache_dir: Option<PathBuf>,
+}
+
+impl BinCommand {
+    /// The crop region described by this command's `crop_*` arguments, if any were given
+    fn crop(&self) -> Option<Crop> {
+        Some(Crop {
+            x0: self.crop_x0?,
+            y0: self.crop_y0?,
+            x1: self.crop_x1?,
+            y1: self.crop_y1?,
+        })
+    }
 }
 
 #[derive(Debug, Clone, Subcommand)]
@@ -57,6 +139,14 @@ struct DebugDatasetsCommand {
 }
 
 fn main() {
+    if let Err(err) = run() {
+        eprintln!("Error: {err}");
+        std::process::exit(1);
+    }
+}
+
+/// Parse arguments and dispatch to the requested command, propagating any failure to `main`
+fn run() -> Result<(), Box<dyn std::error::Error>> {
     _ = dotenvy::dotenv();
     let args = Cli::parse();
     tracing_subscriber::fmt()
@@ -68,7 +158,7 @@ fn main() {
             subcommand: DebugCommands::Datasets(args),
         } => {
             let collection =
-                Collection::from_nexus(args.nexus_path.clone(), args.data_file_padding).unwrap();
+                Collection::from_nexus(args.nexus_path.clone(), args.data_file_padding, 0)?;
 
             for data_file in &args.data_files {
                 match read_hdf5_data(
@@ -84,7 +174,89 @@ fn main() {
                 }
             }
 
-            print_tree(&collection.as_tree()).unwrap();
+            print_tree(&collection.as_tree())?;
+        }
+        Commands::Bin(args) => {
+            let collection = Collection::from_nexus(
+                args.nexus_path.clone(),
+                args.data_file_padding,
+                args.workers,
+            )?;
+
+            let image = match &args.cache_dir {
+                Some(cache_dir) => {
+                    let cached = cache::load(cache_dir, args.width, args.height, args.crop())?;
+                    let (image_width, image_height) = args
+                        .crop()
+                        .map_or((args.width, args.height), |crop| {
+                            (crop.width(), crop.height())
+                        });
+
+                    let (cached_modules, previous_file_counts, docket, rebuild_modules) =
+                        match cached {
+                            Some((cached_modules, docket)) => match collection
+                                .validate_cache(&docket)?
+                            {
+                                cache::CacheStatus::Append {
+                                    previous_file_counts,
+                                } => (cached_modules, previous_file_counts, Some(docket), Vec::new()),
+                                cache::CacheStatus::Stale {
+                                    previous_file_counts,
+                                    rebuild_modules,
+                                } => (cached_modules, previous_file_counts, Some(docket), rebuild_modules),
+                            },
+                            None => (Vec::new(), Vec::new(), None, Vec::new()),
+                        };
+
+                    let module_deltas = collection.bin_delta_per_module(
+                        &previous_file_counts,
+                        &rebuild_modules,
+                        args.width,
+                        args.height,
+                        args.crop(),
+                        args.workers,
+                    )?;
+
+                    let mut image = Image::new(image_width, image_height);
+                    for (module_idx, cached_image) in &cached_modules {
+                        if !rebuild_modules.contains(module_idx) {
+                            image.merge(cached_image);
+                        }
+                    }
+                    for (_, delta) in &module_deltas {
+                        image.merge(delta);
+                    }
+
+                    cache::write(
+                        cache_dir,
+                        &module_deltas,
+                        args.width,
+                        args.height,
+                        args.crop(),
+                        image_width,
+                        image_height,
+                        collection.file_stamps()?,
+                        docket,
+                        &rebuild_modules,
+                    )?;
+
+                    image
+                }
+                None => collection.bin(args.width, args.height, args.crop(), args.workers)?,
+            };
+
+            image.write_hdf5(&args.output)?;
+        }
+        Commands::Explore(args) => {
+            let collection = Collection::from_nexus(
+                args.nexus_path.clone(),
+                args.data_file_padding,
+                args.workers,
+            )?;
+
+            repl::run(&collection, args.width, args.height, args.workers);
         }
     }
+
+    Ok(())
 }
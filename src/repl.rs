@@ -0,0 +1,93 @@
+use crate::collection::Collection;
+use crate::image::Crop;
+use ptree::print_tree;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+/// Run an interactive REPL for exploring `collection` without reopening its files between
+/// commands
+///
+/// Supports `datasets`, `read <key>`, `tree`, and `bin <x0> <y0> <x1> <y1>`, plus `exit`/`quit`
+/// to leave. `width`, `height` and `workers` are used for `bin` in the same way as the `bin`
+/// subcommand.
+pub fn run(collection: &Collection, width: u16, height: u16, workers: usize) {
+    let mut editor = DefaultEditor::new().expect("failed to initialise line editor");
+
+    loop {
+        match editor.readline("tristimg> ") {
+            Ok(line) => {
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                _ = editor.add_history_entry(line);
+
+                let mut words = line.split_whitespace();
+                match words.next() {
+                    Some("datasets") => print_datasets(collection),
+                    Some("read") => match words.next() {
+                        Some(key) => print_dataset(collection, key),
+                        None => eprintln!("usage: read <key>"),
+                    },
+                    Some("tree") => {
+                        if let Err(err) = print_tree(&collection.as_tree()) {
+                            eprintln!("Error printing tree: {err}");
+                        }
+                    }
+                    Some("bin") => {
+                        let bounds: Vec<u16> = words.filter_map(|word| word.parse().ok()).collect();
+                        match bounds[..] {
+                            [x0, y0, x1, y1] => {
+                                print_roi(collection, width, height, Crop { x0, y0, x1, y1 }, workers)
+                            }
+                            _ => eprintln!("usage: bin <x0> <y0> <x1> <y1>"),
+                        }
+                    }
+                    Some("exit" | "quit") => break,
+                    Some(command) => eprintln!("unknown command: {command}"),
+                    None => {}
+                }
+            }
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => break,
+            Err(err) => {
+                eprintln!("Error reading input: {err}");
+                break;
+            }
+        }
+    }
+}
+
+/// Print the dataset keys available in the collection
+fn print_datasets(collection: &Collection) {
+    match collection.dataset_names() {
+        Ok(names) => names.iter().for_each(|name| println!("{name}")),
+        Err(err) => eprintln!("Error listing datasets: {err}"),
+    }
+}
+
+/// Print the values of dataset `key` across every data file in the collection
+fn print_dataset(collection: &Collection, key: &str) {
+    match collection.read_dataset(key) {
+        Ok(values) => values
+            .iter()
+            .enumerate()
+            .for_each(|(file_idx, values)| println!("file {file_idx}: {values:?}")),
+        Err(err) => eprintln!("Error reading {key}: {err}"),
+    }
+}
+
+/// Bin the region of interest described by `crop` and print a quick summary of the result
+fn print_roi(collection: &Collection, width: u16, height: u16, crop: Crop, workers: usize) {
+    match collection.bin(width, height, Some(crop), workers) {
+        Ok(image) => {
+            let total: u64 = image.counts().iter().sum();
+            let max = image.counts().iter().copied().max().unwrap_or(0);
+            println!(
+                "{}x{} ROI: {total} events, peak pixel count {max}",
+                image.width(),
+                image.height()
+            );
+        }
+        Err(err) => eprintln!("Error binning ROI: {err}"),
+    }
+}